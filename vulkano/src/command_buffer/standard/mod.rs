@@ -11,6 +11,7 @@
 //! 
 //! Everything in this module is dedicated to the "standard" implementation of command buffers.
 
+use std::any::Any;
 use std::ops::Range;
 use std::sync::Arc;
 
@@ -66,16 +67,36 @@ pub unsafe trait StdCommandBufferBuilder {
     type ResourcesDependencies: ResourcesDependencies;
 
     /// Adds a buffer update command at the end of the command buffer builder.
+    ///
+    /// `D` must be `Send + Sync + 'static` because a payload too large for `vkCmdUpdateBuffer` is
+    /// staged through a transient buffer that is kept alive past the end of this call, until the
+    /// built command buffer's submission completes. `B` must be `Send + Sync + 'static` because
+    /// the destination buffer is kept alive the same way on every call, whatever the payload
+    /// size; see `StdUpdateBufferBuilder::new`. `D` must also be `Clone`, since staging that same
+    /// payload clones it into the transient buffer rather than taking `data` itself.
     #[inline]
     fn update_buffer<'a, 'b, D: 'a, S, B: 'b>(self, buffer: S, data: &'a D)
                                               -> StdUpdateBufferBuilder<'a, Self, D, B>
         where Self: Sized,
-              B: Buffer,
+              B: Buffer + Send + Sync + 'static,
+              D: Clone + Send + Sync + 'static,
               S: Into<BufferSlice<'b, D, B>>
     {
         StdUpdateBufferBuilder::new(self, buffer, data)
     }
 
+    /// Registers a resource (a buffer, an image, a staging allocation, ...) to be kept alive
+    /// until the command buffer built from this builder has finished executing on the GPU.
+    ///
+    /// Wrapper builders must forward this call to the builder they wrap, so that it ultimately
+    /// reaches the concrete builder that owns the retention list.
+    ///
+    /// Retained resources are released once the submission's fence signals. This normally
+    /// happens lazily, the next time a command buffer is built from the same pool; call
+    /// `StdPrimaryCommandBufferPool::reclaim` directly if recording has paused and transient
+    /// resources such as staging buffers should be freed sooner than that.
+    fn retain(&mut self, resource: Arc<Any + Send + Sync>);
+
     /// Obtains a temporary access to the command buffer builder in order to add one or multiple
     /// commands to it.
     ///
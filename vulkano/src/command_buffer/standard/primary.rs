@@ -7,9 +7,11 @@
 // notice may not be copied, modified, or distributed except
 // according to those terms.
 
+use std::any::Any;
 use std::mem;
 use std::ops::Range;
 use std::sync::Arc;
+use std::sync::Mutex;
 
 use buffer::Buffer;
 use buffer::BufferSlice;
@@ -27,22 +29,122 @@ use command_buffer::sys::UnsafeCommandBufferBuilder;
 use framebuffer::EmptySinglePassRenderPass;
 use image::Image;
 use image::sys::Layout;
+use sync::Fence;
 use sync::PipelineStages;
 use sync::AccessFlagBits;
 
+/// Flags that primary command buffers allocated (or reset) through
+/// `StdPrimaryCommandBufferPool` are recorded with.
+const BUILDER_FLAGS: Flags = Flags::SimultaneousUse;
+
+/// Owns the `CommandPool` that primary command buffers are allocated from, together with the set
+/// of retired command buffers that are waiting on their completion fence before they can be reset
+/// and handed back out.
+///
+/// Submission should push the finished command buffer and its fence here with `recycle` instead
+/// of letting them drop. `reclaim` resets every retired entry whose fence has already signalled
+/// and stashes the now-empty allocation in `ready`, where `StdPrimaryCommandBufferBuilder::new`
+/// picks it up instead of allocating a fresh one from the underlying `CommandPool`.
+///
+/// Each retired entry also carries the resources that were registered with `retain` while the
+/// command buffer was being built (staging buffers, and the like). Reclaiming the entry drops
+/// them, which is the only point at which it is safe to do so: the fence being signalled is the
+/// proof that the GPU is done reading from them.
+pub struct StdPrimaryCommandBufferPool<P> where P: CommandPool {
+    pool: P,
+    retired: Mutex<Vec<(UnsafeCommandBuffer<P>, Fence, Vec<Arc<Any + Send + Sync>>)>>,
+    ready: Mutex<Vec<UnsafeCommandBufferBuilder<P>>>,
+}
+
+impl<P> StdPrimaryCommandBufferPool<P> where P: CommandPool + Clone {
+    /// Builds a new pool on top of `pool`, with nothing retired yet.
+    #[inline]
+    pub fn new(pool: P) -> StdPrimaryCommandBufferPool<P> {
+        StdPrimaryCommandBufferPool {
+            pool: pool,
+            retired: Mutex::new(Vec::new()),
+            ready: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Pushes a command buffer that has just been submitted, along with the fence that will be
+    /// signalled once the GPU is done executing it and the resources it touched that must be
+    /// kept alive until then, so that it can be reclaimed later on.
+    #[inline]
+    pub fn recycle(&self, cb: UnsafeCommandBuffer<P>, fence: Fence,
+                   retained: Vec<Arc<Any + Send + Sync>>) {
+        self.retired.lock().unwrap().push((cb, fence, retained));
+    }
+
+    /// Resets every retired entry whose fence has already signalled and moves the now-empty
+    /// allocation into the pool of builders ready to be handed out, releasing the resources the
+    /// entry was keeping alive in the process.
+    ///
+    /// `StdPrimaryCommandBufferBuilder::new` calls this itself, so there is no need to call it
+    /// before building a command buffer. Call it directly if you have stopped recording for a
+    /// while and want transient resources such as staging buffers freed without waiting for the
+    /// next `StdPrimaryCommandBufferBuilder::new`.
+    pub fn reclaim(&self) {
+        let signalled: Vec<_> = {
+            let mut retired = self.retired.lock().unwrap();
+            let mut signalled = Vec::new();
+            let mut i = 0;
+            while i < retired.len() {
+                if retired[i].1.ready().unwrap_or(false) {
+                    signalled.push(retired.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            signalled
+        };
+
+        if signalled.is_empty() { return; }
+
+        let kind = Kind::Primary::<EmptySinglePassRenderPass, EmptySinglePassRenderPass>;
+        let mut ready = self.ready.lock().unwrap();
+
+        for (cb, _fence, _retained) in signalled {
+            // Safe to reset: the fence above proves the GPU is done with this command buffer,
+            // and `_retained` drops here, which is likewise only safe once that is true.
+            // TODO: allow handling this error
+            let builder = unsafe {
+                self.pool.reset_command_buffer(cb, kind, BUILDER_FLAGS)
+            }.unwrap();
+            ready.push(builder);
+        }
+    }
+}
+
 pub struct StdPrimaryCommandBufferBuilder<P = Arc<StandardCommandPool>> where P: CommandPool {
     inner: UnsafeCommandBufferBuilder<P>,
     staging_barrier: PipelineBarrierBuilder,
+    pool: Arc<StdPrimaryCommandBufferPool<P>>,
+    retained: Vec<Arc<Any + Send + Sync>>,
 }
 
-impl<P> StdPrimaryCommandBufferBuilder<P> where P: CommandPool {
-    pub fn new(pool: P) -> StdPrimaryCommandBufferBuilder<P> {
-        let kind = Kind::Primary::<EmptySinglePassRenderPass, EmptySinglePassRenderPass>;
-        let cb = UnsafeCommandBufferBuilder::new(pool, kind, Flags::SimultaneousUse).unwrap();  // TODO: allow handling this error
+impl<P> StdPrimaryCommandBufferBuilder<P> where P: CommandPool + Clone {
+    /// Builds a new primary command buffer builder, first reclaiming any of `pool`'s retired
+    /// command buffers whose fence has already signalled. A reclaimed, reset allocation is
+    /// reused if one is available; otherwise a fresh one is allocated from the underlying
+    /// `CommandPool`.
+    pub fn new(pool: Arc<StdPrimaryCommandBufferPool<P>>) -> StdPrimaryCommandBufferBuilder<P> {
+        pool.reclaim();
+
+        let cb = match pool.ready.lock().unwrap().pop() {
+            Some(cb) => cb,
+            None => {
+                let kind = Kind::Primary::<EmptySinglePassRenderPass, EmptySinglePassRenderPass>;
+                // TODO: allow handling this error
+                UnsafeCommandBufferBuilder::new(pool.pool.clone(), kind, BUILDER_FLAGS).unwrap()
+            },
+        };
 
         StdPrimaryCommandBufferBuilder {
             inner: cb,
             staging_barrier: PipelineBarrierBuilder::new(),
+            pool: pool,
+            retained: Vec::new(),
         }
     }
 }
@@ -52,11 +154,16 @@ unsafe impl<P> StdCommandBufferBuilder for StdPrimaryCommandBufferBuilder<P> whe
     type Pool = P;
     type ResourcesDependencies = PipelineBarrierBuilder;
 
+    #[inline]
+    fn retain(&mut self, resource: Arc<Any + Send + Sync>) {
+        self.retained.push(resource);
+    }
+
     #[inline]
     unsafe fn add_command<F>(&mut self, cmd: F)
         where F: FnOnce(&mut UnsafeCommandBufferBuilder<P>)
     {
-        if !staging_barrier.is_empty() {
+        if !self.staging_barrier.is_empty() {
             self.inner.pipeline_barrier(mem::replace(&mut self.staging_barrier,
                                                      PipelineBarrierBuilder::new()));
         }
@@ -82,13 +189,49 @@ unsafe impl<P> StdCommandBufferBuilder for StdPrimaryCommandBufferBuilder<P> whe
 
         StdPrimaryCommandBuffer {
             inner: self.inner.build().unwrap(),     // TODO: allow handling this error
+            pool: self.pool,
+            retained: self.retained,
         }
     }
 }
 
 pub struct StdPrimaryCommandBuffer<P = Arc<StandardCommandPool>> where P: CommandPool {
-    inner: UnsafeCommandBuffer<P>
+    inner: UnsafeCommandBuffer<P>,
+    pool: Arc<StdPrimaryCommandBufferPool<P>>,
+    retained: Vec<Arc<Any + Send + Sync>>,
+}
+
+impl<P> StdPrimaryCommandBuffer<P> where P: CommandPool {
+    /// Splits this command buffer back into its raw Vulkan object, the pool it was allocated
+    /// from and the resources it keeps alive, so that the submission code can hand all three to
+    /// `StdPrimaryCommandBufferPool::recycle` together with the fence guarding the submission.
+    #[inline]
+    pub fn into_raw_parts(self) -> (UnsafeCommandBuffer<P>, Arc<StdPrimaryCommandBufferPool<P>>,
+                                     Vec<Arc<Any + Send + Sync>>) {
+        (self.inner, self.pool, self.retained)
+    }
+}
+
+impl<P> StdPrimaryCommandBuffer<P> where P: CommandPool + Clone {
+    /// Submits this command buffer by calling `submit` with its raw Vulkan object, then
+    /// registers the command buffer with its pool against `fence` so that it (and everything it
+    /// was keeping alive) can be reclaimed once `fence` signals.
+    ///
+    /// `fence` must be the fence that guards this exact submission, typically created alongside
+    /// the call to `vkQueueSubmit` that `submit` performs.
+    pub fn submit<F>(self, fence: Fence, submit: F) where F: FnOnce(&UnsafeCommandBuffer<P>) {
+        let (inner, pool, retained) = self.into_raw_parts();
+        submit(&inner);
+        pool.recycle(inner, fence, retained);
+    }
 }
 
 unsafe impl<P> StdCommandBuffer for StdPrimaryCommandBuffer<P> where P: CommandPool {
+    type TransitionCommandBuffer = UnsafeCommandBuffer<P>;
+
+    #[inline]
+    fn build_required_transitions(&self) -> Option<UnsafeCommandBuffer<P>> {
+        // TODO: final image transitions
+        None
+    }
 }
@@ -7,12 +7,15 @@
 // notice may not be copied, modified, or distributed except
 // according to those terms.
 
+use std::any::Any;
 use std::mem;
 use std::ops::Range;
 use std::sync::Arc;
 
 use buffer::Buffer;
 use buffer::BufferSlice;
+use buffer::BufferUsage;
+use buffer::cpu_access::CpuAccessibleBuffer;
 use command_buffer::standard::LatestBufferUsage;
 use command_buffer::standard::StdCommandBuffer;
 use command_buffer::standard::StdCommandBufferBuilder;
@@ -24,20 +27,43 @@ use sync::PipelineStages;
 
 use VulkanObject;
 
+/// Maximum size in bytes of the data that `vkCmdUpdateBuffer` accepts inline. Anything larger has
+/// to go through a staging buffer and a `vkCmdCopyBuffer` instead.
+const INLINE_UPDATE_LIMIT: usize = 65536;
+
 /// Wrapper around a `StdCommandBufferBuilder` that adds a buffer updating command at the end of
 /// the builder.
+///
+/// If `data` fits within the inline limit of `vkCmdUpdateBuffer`, it is recorded directly.
+/// Otherwise a transient host-visible staging buffer is allocated, `data` is copied into it, and
+/// a `vkCmdCopyBuffer` is recorded instead. Both the destination buffer and, when allocated, the
+/// staging buffer are registered with `StdCommandBufferBuilder::retain` so that they are kept
+/// alive until the GPU has actually finished executing the command buffer, rather than for as
+/// long as this wrapper happens to stay around.
 pub struct StdUpdateBufferBuilder<'a, T, D: 'a, B> {
     inner: T,
-    data: &'a D,
+    command: UpdateBufferCommand<'a, D>,
     buffer: Arc<B>,
+    offset: usize,
+    size: usize,
     flushed: bool,
 }
 
+/// The actual operation that `StdUpdateBufferBuilder` will record into the command buffer.
+enum UpdateBufferCommand<'a, D: 'a> {
+    /// Small enough to be passed directly to `vkCmdUpdateBuffer`.
+    Inline(&'a D),
+    /// Too large for `vkCmdUpdateBuffer`. A clone of `data` has already been copied into this
+    /// staging buffer, which must be brought in with a `vkCmdCopyBuffer` instead.
+    Staging(Arc<CpuAccessibleBuffer<D>>),
+}
+
 impl<'a, T, D: 'a, B> StdUpdateBufferBuilder<'a, T, D, B> where T: StdCommandBufferBuilder {
     /// Adds the command at the end of `inner`.
-    pub fn new<'b, S>(inner: T, buffer: S, data: &'a D) -> StdUpdateBufferBuilder<'a, T, D, B>
+    pub fn new<'b, S>(mut inner: T, buffer: S, data: &'a D) -> StdUpdateBufferBuilder<'a, T, D, B>
         where S: Into<BufferSlice<'b, D, B>>,
-              B: Buffer + 'b
+              B: Buffer + Send + Sync + 'static + 'b,
+              D: Clone + Send + Sync + 'static
     {
         let buffer = buffer.into();
 
@@ -46,9 +72,45 @@ impl<'a, T, D: 'a, B> StdUpdateBufferBuilder<'a, T, D, B> where T: StdCommandBuf
         // TODO: return error instead
         assert_eq!(buffer.offset() % 4, 0);
         assert_eq!(buffer.size() % 4, 0);
-        assert!(mem::size_of_val(data) <= 65536);
         assert!(buffer.buffer().inner_buffer().usage_transfer_dest());
 
+        let command = if mem::size_of_val(data) <= INLINE_UPDATE_LIMIT {
+            UpdateBufferCommand::Inline(data)
+
+        } else {
+            // The payload doesn't fit in a vkCmdUpdateBuffer, so stage it through a transient
+            // host-visible buffer and copy it into place instead.
+            let usage = BufferUsage { transfer_source: true, .. BufferUsage::none() };
+            let device = buffer.buffer().inner_buffer().device().clone();
+
+            // `from_data` takes its payload by value, and `data` here is only a borrow, so clone
+            // it rather than bit-copying `*data` into the staging buffer: a raw memcpy would hand
+            // out a second owner of any heap allocation or refcount `D` holds without running
+            // `Clone`, while the original `data` is still owned by the caller.
+            // TODO: return error instead
+            let staging = CpuAccessibleBuffer::from_data(device, usage, data.clone())
+                                               .expect("failed to allocate staging buffer");
+
+            // Keep the staging buffer alive only until the GPU is done executing the copy, not
+            // for as long as this wrapper or the command buffer it builds happen to stick around.
+            inner.retain(staging.clone());
+
+            // The copy above is a plain host write; make it visible to the `vkCmdCopyBuffer`
+            // that will read from this buffer by requiring transfer-read availability on it,
+            // mirroring the destination buffer's own transition below.
+            {
+                let stages = PipelineStages { transfer: true, .. PipelineStages::none() };
+                let access = AccessFlagBits { transfer_read: true, .. AccessFlagBits::none() };
+                let staging_slice: BufferSlice<D, CpuAccessibleBuffer<D>> = (&staging).into();
+                inner.transition_buffer_state(staging_slice, stages, access, true);
+            }
+
+            UpdateBufferCommand::Staging(staging)
+        };
+
+        // The destination buffer must likewise outlive the GPU's execution of this command.
+        inner.retain(buffer.buffer().clone());
+
         // Now that we know the command is valid, we request the right state.
         {
             let stages = PipelineStages { transfer: true, .. PipelineStages::none() };
@@ -58,8 +120,10 @@ impl<'a, T, D: 'a, B> StdUpdateBufferBuilder<'a, T, D, B> where T: StdCommandBuf
 
         StdUpdateBufferBuilder {
             inner: inner,
-            data: data,
+            command: command,
             buffer: buffer.buffer().clone(),
+            offset: buffer.offset(),
+            size: buffer.size(),
             flushed: false,
         }
     }
@@ -69,7 +133,24 @@ impl<'a, T, D: 'a, B> StdUpdateBufferBuilder<'a, T, D, B> where T: StdCommandBuf
             if self.flushed { return; }
             self.flushed = true;
 
-            self.inner.add_command(|cb| unimplemented!());
+            let buffer = self.buffer.clone();
+            let offset = self.offset;
+            let size = self.size;
+
+            match self.command {
+                UpdateBufferCommand::Inline(data) => {
+                    self.inner.add_command(move |cb| {
+                        cb.update_buffer(&buffer, offset, data);
+                    });
+                },
+
+                UpdateBufferCommand::Staging(ref staging) => {
+                    let staging = staging.clone();
+                    self.inner.add_command(move |cb| {
+                        cb.copy_buffer(&staging, 0, &buffer, offset, size);
+                    });
+                },
+            }
         }
     }
 }
@@ -78,12 +159,17 @@ unsafe impl<'a, T, D: 'a, B> StdCommandBufferBuilder for StdUpdateBufferBuilder<
     where T: StdCommandBufferBuilder,
           B: Buffer
 {
-    type BuildOutput = StdUpdateBuffer<T::BuildOutput, B>;
+    type BuildOutput = StdUpdateBuffer<T::BuildOutput>;
     type Pool = T::Pool;
 
     // The second parameter is whether or not to flush before submitting the barrier.
     type BarrierPrototype = (T::BarrierPrototype, bool);
 
+    #[inline]
+    fn retain(&mut self, resource: Arc<Any + Send + Sync>) {
+        self.inner.retain(resource);
+    }
+
     #[inline]
     unsafe fn add_command<F>(&mut self, cmd: F)
         where F: FnOnce(&mut UnsafeCommandBufferBuilder<T::Pool>)
@@ -114,22 +200,42 @@ unsafe impl<'a, T, D: 'a, B> StdCommandBufferBuilder for StdUpdateBufferBuilder<
     }
 
     #[inline]
-    fn build(mut self) -> StdUpdateBuffer<T::BuildOutput, B> {
+    fn build(mut self) -> StdUpdateBuffer<T::BuildOutput> {
         self.flush();
 
         StdUpdateBuffer {
             inner: self.inner.build(),
-            buffer: self.buffer,
         }
     }
 }
 
 /// Wrapper around a `StdUpdateBuffer` that adds a buffer updating command at the end of the
 /// command buffer.
-pub struct StdUpdateBuffer<T, B> {
+///
+/// The destination and (if any) staging buffers involved in the update are not stored here: they
+/// were registered with `StdCommandBufferBuilder::retain` while this wrapper was being built, so
+/// they are already tracked by the concrete command buffer's retention list.
+pub struct StdUpdateBuffer<T> {
     inner: T,
-    buffer: Arc<B>,
 }
 
-unsafe impl<T, B> StdCommandBuffer for StdUpdateBuffer<T, B> where T: StdCommandBuffer {
+impl<T> StdUpdateBuffer<T> {
+    /// Unwraps this wrapper and returns the command buffer it wraps.
+    ///
+    /// Submitting a command buffer and reclaiming the resources it keeps alive are tied together
+    /// (see `StdPrimaryCommandBuffer::submit`), so peel wrapper layers off with this until you
+    /// reach the concrete command buffer type instead of trying to do either generically.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+unsafe impl<T> StdCommandBuffer for StdUpdateBuffer<T> where T: StdCommandBuffer {
+    type TransitionCommandBuffer = T::TransitionCommandBuffer;
+
+    #[inline]
+    fn build_required_transitions(&self) -> Option<T::TransitionCommandBuffer> {
+        self.inner.build_required_transitions()
+    }
 }